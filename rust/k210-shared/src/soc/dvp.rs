@@ -1,4 +1,7 @@
+use core::cell::Cell;
 use core::cmp;
+use core::convert::Infallible;
+use embedded_hal::blocking::i2c;
 use k210_hal::pac;
 use pac::dvp;
 
@@ -11,7 +14,14 @@ pub trait DVPExt: Sized {
 }
 
 impl DVPExt for pac::DVP {
-    fn constrain(self) -> DVP { DVP { dvp: self, sccb_addr_len: sccb_addr_len::W8 } }
+    fn constrain(self) -> DVP {
+        DVP {
+            dvp: self,
+            sccb_addr_len: sccb_addr_len::W8,
+            sccb_timeout: 0,
+            stats: dvp_stats::new(),
+        }
+    }
 }
 
 /** Output mode (RGB565 for display, or for planar for AI) */
@@ -35,9 +45,57 @@ pub enum interrupt {
     frame_finish,
 }
 
+/** A single step in an SCCB register-programming table.
+ *
+ * Sensor bring-up sequences are long lists of register writes interleaved with
+ * settle delays and read-modify-write masks; expressing one as a
+ * `&'static [sccb_op]` lets a downstream sensor crate ship and replay it with a
+ * single `apply_regs` call.
+ */
+#[derive(Copy, Clone)]
+pub enum sccb_op {
+    /** Write `val` to register `reg` */
+    write(u16, u8),
+    /** Read-modify-write: replace the `mask` bits of `reg` with `val` */
+    modify(u16, u8, u8),
+    /** Busy-wait for the given number of microseconds */
+    delay_us(u32),
+}
+
+/** Runtime counters accumulated in the capture and SCCB paths.
+ *
+ * Interior mutability lets the counters tick from the shared-`&self` SCCB path
+ * as well as from the `&mut self` capture path; read them with e.g.
+ * `dvp.stats().frames_finished.get()`.
+ */
+#[derive(Default)]
+pub struct dvp_stats {
+    /** Frames armed with `start_capture`, plus every `frame_start` seen by `ping_pong` */
+    pub frames_started: Cell<u32>,
+    /** Frames that completed conversion */
+    pub frames_finished: Cell<u32>,
+    /** Frames overwritten because no buffer was free to receive them */
+    pub frames_dropped: Cell<u32>,
+    /** SCCB transfers abandoned after hitting the spin-loop bound */
+    pub sccb_timeouts: Cell<u32>,
+}
+
+impl dvp_stats {
+    const fn new() -> Self {
+        dvp_stats {
+            frames_started: Cell::new(0),
+            frames_finished: Cell::new(0),
+            frames_dropped: Cell::new(0),
+            sccb_timeouts: Cell::new(0),
+        }
+    }
+}
+
 pub struct DVP {
     dvp: pac::DVP,
     sccb_addr_len: sccb_addr_len,
+    sccb_timeout: u32,
+    stats: dvp_stats,
 }
 
 pub type image_format = dvp::dvp_cfg::FORMATW;
@@ -71,20 +129,36 @@ impl DVP {
         return sysctl::clock_get_freq(sysctl::clock::DVP) / (v_period_clk_cnt * 2);
     }
 
-    /** Perform, and wait for a SCCB transfer (read or write) */
-    fn sccb_start_transfer(&self) {
+    /** Spin until the SCCB engine goes idle, bounded by `sccb_timeout`.
+     *
+     * A `sccb_timeout` of 0 spins forever (the original behavior); otherwise it
+     * gives up after that many polls, bumps `sccb_timeouts` and returns
+     * `error::timeout` so a missing camera stalls the driver instead of hanging.
+     */
+    fn wait_sccb_idle(&self) -> Result<(), error> {
+        let mut spins = self.sccb_timeout;
         while self.dvp.sts.read().sccb_en().bit() {
-            // IDLE
+            if self.sccb_timeout != 0 {
+                spins -= 1;
+                if spins == 0 {
+                    self.stats.sccb_timeouts.set(self.stats.sccb_timeouts.get() + 1);
+                    return Err(error::timeout);
+                }
+            }
         }
+        Ok(())
+    }
+
+    /** Perform, and wait for a SCCB transfer (read or write) */
+    fn sccb_start_transfer(&self) -> Result<(), error> {
+        self.wait_sccb_idle()?;
         self.dvp.sts.write(|w| w.sccb_en().set_bit()
                                 .sccb_en_we().set_bit());
-        while self.dvp.sts.read().sccb_en().bit() {
-            // IDLE
-        }
+        self.wait_sccb_idle()
     }
 
     /** Set a register value through SCCB */
-    pub fn sccb_send_data(&self, dev_addr: u8, reg_addr: u16, reg_data: u8) {
+    pub fn sccb_send_data(&self, dev_addr: u8, reg_addr: u16, reg_data: u8) -> Result<(), error> {
         use dvp::sccb_cfg::BYTE_NUMW::*;
         unsafe {
             match self.sccb_addr_len {
@@ -103,11 +177,11 @@ impl DVP {
                 },
             }
         }
-        self.sccb_start_transfer();
+        self.sccb_start_transfer()
     }
 
     /** Receive register value through SCCB */
-    pub fn sccb_receive_data(&self, dev_addr: u8, reg_addr: u16) -> u8 {
+    pub fn sccb_receive_data(&self, dev_addr: u8, reg_addr: u16) -> Result<u8, error> {
         // Write read request
         use dvp::sccb_cfg::BYTE_NUMW::*;
         unsafe {
@@ -125,11 +199,11 @@ impl DVP {
                 },
             }
         }
-        self.sccb_start_transfer();
+        self.sccb_start_transfer()?;
         // Start read transfer
         unsafe { self.dvp.sccb_ctl.write(|w| w.device_address().bits(dev_addr)); }
-        self.sccb_start_transfer();
-        self.dvp.sccb_cfg.read().rdata().bits()
+        self.sccb_start_transfer()?;
+        Ok(self.dvp.sccb_cfg.read().rdata().bits())
     }
 
     /** Reset DVP-connected device */
@@ -259,27 +333,69 @@ impl DVP {
                                 .frame_finish_we().set_bit());
     }
 
-    /** Wait for an entire frame to complete */
-    pub fn get_image(&self) {
-        while !self.dvp.sts.read().frame_start().bit() {
-            // IDLE
-        }
-        self.dvp.sts.write(|w| w.frame_start().set_bit()
-                                .frame_start_we().set_bit());
-        while !self.dvp.sts.read().frame_start().bit() {
-            // IDLE
-        }
+    /** Arm the peripheral for a single capture.
+     *
+     * The output addresses (`set_display_addr`/`set_ai_addr`), outputs
+     * (`set_output_enable`) and frame mode (`enable_auto`/`disable_auto`) are
+     * expected to be configured already. This clears any pending
+     * `frame_finish` flag and sets `dvp_en` so the next frame is converted; use
+     * `poll_capture` to learn when it has completed.
+     *
+     * The DVP latches output addresses and begins DMA at the next
+     * `frame_start`, so arming part-way through a frame does not produce a torn
+     * capture: the in-progress frame is skipped and the first whole frame after
+     * `dvp_en` is the one returned. Note that this differs from the old
+     * `get_image`, which synchronized to two `frame_start` edges by hand before
+     * asserting `dvp_en`.
+     */
+    pub fn start_capture(&mut self) {
+        self.arm_en();
+        self.stats.frames_started.set(self.stats.frames_started.get() + 1);
+    }
+
+    /** Clear any pending `frame_finish` and assert `dvp_en`.
+     *
+     * The bare arm shared by the blocking single-shot path (`start_capture`,
+     * which also counts the frame) and the continuous `ping_pong` path (which
+     * counts each frame at `frame_start` instead, so it must not count here).
+     */
+    fn arm_en(&mut self) {
         self.dvp.sts.write(|w| w.frame_finish().set_bit()
                                 .frame_finish_we().set_bit()
-                                .frame_start().set_bit()
-                                .frame_start_we().set_bit()
                                 .dvp_en().set_bit()
                                 .dvp_en_we().set_bit());
-        while !self.dvp.sts.read().frame_finish().bit() {
-            // IDLE
+    }
+
+    /** Poll for completion of a capture armed with `start_capture`.
+     *
+     * Returns `Ok(())` once the frame is done (clearing `frame_finish` in the
+     * process) and `Err(nb::Error::WouldBlock)` while it is still in flight.
+     * Capture never fails, hence the `Infallible` error type; this can be
+     * driven straight from the `frame_finish` ISR instead of spinning.
+     */
+    pub fn poll_capture(&mut self) -> nb::Result<(), Infallible> {
+        if self.dvp.sts.read().frame_finish().bit() {
+            self.dvp.sts.write(|w| w.frame_finish().set_bit()
+                                    .frame_finish_we().set_bit());
+            self.stats.frames_finished.set(self.stats.frames_finished.get() + 1);
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
         }
     }
 
+    /** Wait for an entire frame to complete.
+     *
+     * Blocking wrapper over `start_capture` + `poll_capture`. As documented on
+     * `start_capture`, the DVP begins DMA at the next `frame_start`, so the
+     * returned frame is always whole; unlike the previous implementation this
+     * no longer discards an already-in-progress frame up front.
+     */
+    pub fn get_image(&mut self) {
+        self.start_capture();
+        let _ = nb::block!(self.poll_capture());
+    }
+
     /** Configure interrupt */
     pub fn config_interrupt(&self, interrupt: interrupt, enable: bool) {
         match interrupt {
@@ -339,4 +455,339 @@ impl DVP {
         }
     }
 
+    /** Borrow the SCCB bus as an `embedded-hal` I2C master */
+    pub fn sccb(&self) -> sccb<'_> {
+        sccb { dvp: self }
+    }
+
+    /** Bound the SCCB idle spin loop to `polls` iterations (0 = unbounded).
+     *
+     * With a non-zero bound a transfer to an absent camera returns
+     * `error::timeout` instead of hanging forever.
+     */
+    pub fn set_sccb_timeout(&mut self, polls: u32) {
+        self.sccb_timeout = polls;
+    }
+
+    /** Runtime counters accumulated since the last `reset_stats` */
+    pub fn stats(&self) -> &dvp_stats {
+        &self.stats
+    }
+
+    /** Clear all runtime counters */
+    pub fn reset_stats(&mut self) {
+        self.stats = dvp_stats::new();
+    }
+
+    /** Apply a table of SCCB register operations to `dev_addr` in order */
+    pub fn apply_regs(&self, dev_addr: u8, regs: &[sccb_op]) -> Result<(), error> {
+        for op in regs {
+            match *op {
+                sccb_op::write(reg, val) => {
+                    self.sccb_send_data(dev_addr, reg, val)?;
+                }
+                sccb_op::modify(reg, mask, val) => {
+                    let cur = self.sccb_receive_data(dev_addr, reg)?;
+                    self.sccb_send_data(dev_addr, reg, (cur & !mask) | (val & mask))?;
+                }
+                sccb_op::delay_us(us) => {
+                    usleep(us as usize);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /** Read back a table of register operations, returning the first mismatch.
+     *
+     * `write`/`modify` entries are read back and compared (only the masked bits
+     * for `modify`); `delay_us` entries are skipped. On mismatch the register
+     * address along with the expected and actual values is returned.
+     */
+    pub fn verify_regs(&self, dev_addr: u8, regs: &[sccb_op]) -> Result<Option<(u16, u8, u8)>, error> {
+        for op in regs {
+            match *op {
+                sccb_op::write(reg, val) => {
+                    let got = self.sccb_receive_data(dev_addr, reg)?;
+                    if got != val {
+                        return Ok(Some((reg, val, got)));
+                    }
+                }
+                sccb_op::modify(reg, mask, val) => {
+                    let got = self.sccb_receive_data(dev_addr, reg)?;
+                    if (got & mask) != (val & mask) {
+                        return Ok(Some((reg, val & mask, got & mask)));
+                    }
+                }
+                sccb_op::delay_us(_) => {}
+            }
+        }
+        Ok(None)
+    }
+
+}
+
+/** Error raised by the SCCB bus when driven as an I2C master */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum error {
+    /** Transaction does not fit the `sccb_ctl` register sequence.
+     *
+     * The hardware latches the device address plus at most three further bytes
+     * (`reg_address`, `wdata_byte0`, `wdata_byte1`), so a write carries one to
+     * three bytes in total (register address followed by data), and a read
+     * sends exactly the register address (1 or 2 bytes, per `sccb_addr_len`)
+     * and returns exactly one byte. Empty writes and reads of any other length
+     * are rejected with this error as well.
+     */
+    too_long,
+    /** The SCCB engine did not go idle within the configured spin-loop bound */
+    timeout,
+}
+
+/** I2C master view of the SCCB bus.
+ *
+ * SCCB is electrically and protocol-wise a subset of I2C, so this thin wrapper
+ * lets generic sensor driver crates (OV2640/OV5640/…) talk to the camera over
+ * the DVP peripheral instead of hand-rolling register pokes. The register
+ * address width follows the `sccb_addr_len` the [`DVP`] was configured with.
+ */
+pub struct sccb<'a> {
+    dvp: &'a DVP,
+}
+
+impl<'a> sccb<'a> {
+    /** Number of register-address bytes implied by the configured width */
+    fn addr_bytes(&self) -> usize {
+        match self.dvp.sccb_addr_len {
+            sccb_addr_len::W8 => 1,
+            sccb_addr_len::W16 => 2,
+        }
+    }
+
+    /** Drive a write of `bytes` (register address followed by data) to `dev_addr` */
+    fn write_bytes(&self, dev_addr: u8, bytes: &[u8]) -> Result<(), error> {
+        use dvp::sccb_cfg::BYTE_NUMW::*;
+        let byte_num = match bytes.len() {
+            1 => NUM2,
+            2 => NUM3,
+            3 => NUM4,
+            _ => return Err(error::too_long),
+        };
+        unsafe {
+            self.dvp.dvp.sccb_cfg.modify(|_,w| w.byte_num().variant(byte_num));
+            self.dvp.dvp.sccb_ctl.write(|w| {
+                let w = w.device_address().bits(dev_addr | 1)
+                         .reg_address().bits(bytes[0]);
+                let w = if bytes.len() > 1 { w.wdata_byte0().bits(bytes[1]) } else { w };
+                if bytes.len() > 2 { w.wdata_byte1().bits(bytes[2]) } else { w }
+            });
+        }
+        self.dvp.sccb_start_transfer()
+    }
+
+    /** Drive the read phase and return the single byte latched in `rdata` */
+    fn read_byte(&self, dev_addr: u8) -> Result<u8, error> {
+        unsafe { self.dvp.dvp.sccb_ctl.write(|w| w.device_address().bits(dev_addr)); }
+        self.dvp.sccb_start_transfer()?;
+        Ok(self.dvp.dvp.sccb_cfg.read().rdata().bits())
+    }
+}
+
+/** Convert an `embedded-hal` 7-bit device address into the 8-bit wire byte.
+ *
+ * The `i2c::{Write,WriteRead,Read}` traits pass `address` right-aligned in 7
+ * bits, whereas `sccb_ctl.device_address` (and the `sccb_send_data` /
+ * `sccb_receive_data` convention) is driven with the full 8-bit wire byte
+ * including the R/W bit position — e.g. an OV2640 at 7-bit `0x30` is `0x60` on
+ * the wire. Shifting here keeps the trait impls faithful to the embedded-hal
+ * addressing contract, so a generic sensor crate can pass its 7-bit address.
+ */
+fn wire_addr(address: u8) -> u8 {
+    address << 1
+}
+
+impl i2c::Write for sccb<'_> {
+    type Error = error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.write_bytes(wire_addr(address), bytes)
+    }
+}
+
+impl i2c::WriteRead for sccb<'_> {
+    type Error = error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Self::Error> {
+        // A read carries the register address (matching the configured width)
+        // and returns exactly one byte.
+        if bytes.len() != self.addr_bytes() || buffer.len() != 1 {
+            return Err(error::too_long);
+        }
+        let dev_addr = wire_addr(address);
+        self.write_bytes(dev_addr, bytes)?;
+        buffer[0] = self.read_byte(dev_addr)?;
+        Ok(())
+    }
+}
+
+impl i2c::Read for sccb<'_> {
+    type Error = error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        if buffer.len() != 1 {
+            return Err(error::too_long);
+        }
+        buffer[0] = self.read_byte(wire_addr(address))?;
+        Ok(())
+    }
+}
+
+/** A capture target handed to the ping-pong manager.
+ *
+ * Holds the raw framebuffer pointer(s) for one of the two output paths; which
+ * variant is used has to match the output the [`DVP`] has enabled.
+ */
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum frame_buf {
+    /** 16-bit R5G6B5 display buffer, programmed with `set_display_addr` */
+    display(*mut u16),
+    /** Planar RGB buffer, programmed with `set_ai_addr` */
+    ai { r: *mut u8, g: *mut u8, b: *mut u8 },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum buf_state {
+    /** Available to hand to the peripheral */
+    free,
+    /** Programmed into the address register, latched at the next `frame_start` */
+    staged,
+    /** Currently being written by the peripheral */
+    in_flight,
+    /** Completed, waiting for the consumer to take it */
+    ready,
+    /** Handed out to the consumer */
+    taken,
+}
+
+/** Double-buffered (ping-pong) continuous capture manager.
+ *
+ * The caller supplies `N` framebuffers up front. Because the DVP latches its
+ * output address at `frame_start`, glitch-free streaming requires the successor
+ * buffer to be programmed a full frame ahead: `on_frame_start` stages the next
+ * free buffer via `set_display_addr`/`set_ai_addr` while the current frame is
+ * still being written, and `on_frame_finish` promotes that staged buffer and
+ * hands the just-completed one back through `take_frame`. Wiring both the
+ * `frame_start` and `frame_finish` interrupts lets this run with `enable_auto`
+ * without the caller ever touching DVP registers mid-frame.
+ */
+pub struct ping_pong<'a, const N: usize> {
+    dvp: &'a mut DVP,
+    bufs: [frame_buf; N],
+    state: [buf_state; N],
+    in_flight: Option<usize>,
+    staged: Option<usize>,
+}
+
+impl<'a, const N: usize> ping_pong<'a, N> {
+    /** Create a manager over `bufs`, leaving the peripheral disarmed */
+    pub fn new(dvp: &'a mut DVP, bufs: [frame_buf; N]) -> Self {
+        ping_pong { dvp, bufs, state: [buf_state::free; N], in_flight: None, staged: None }
+    }
+
+    /** Point the peripheral at the buffer in slot `i` */
+    fn program(&self, i: usize) {
+        match self.bufs[i] {
+            frame_buf::display(addr) => self.dvp.set_display_addr(addr),
+            frame_buf::ai { r, g, b } => self.dvp.set_ai_addr(r, g, b),
+        }
+    }
+
+    /** Index of the first buffer in the given state, if any */
+    fn find(&self, want: buf_state) -> Option<usize> {
+        self.state.iter().position(|&s| s == want)
+    }
+
+    /** Arm the first buffer and start automatic, continuous capture */
+    pub fn start(&mut self) {
+        if let Some(i) = self.find(buf_state::free) {
+            self.program(i);
+            self.state[i] = buf_state::in_flight;
+            self.in_flight = Some(i);
+            self.dvp.enable_auto();
+            // Continuous mode counts each frame at `frame_start`, so arm
+            // without `start_capture`'s per-frame increment.
+            self.dvp.arm_en();
+        }
+    }
+
+    /** Stage the successor buffer a full frame ahead on a `frame_start` event.
+     *
+     * Intended to be called from the `frame_start` ISR. The DVP latches its
+     * output address at `frame_start`, so the buffer for the *next* frame has
+     * to be programmed now, while the current frame is still being written —
+     * the one-frame-ahead latch of the classic DMA double-buffer pattern,
+     * which gives a whole frame of margin rather than just the vertical blank.
+     * If no buffer is free nothing is staged and the in-flight buffer will be
+     * overwritten, which `on_frame_finish` accounts for as a dropped frame.
+     */
+    pub fn on_frame_start(&mut self) {
+        self.dvp.clear_interrupt(interrupt::frame_start);
+        let started = &self.dvp.stats.frames_started;
+        started.set(started.get() + 1);
+        if self.staged.is_none() {
+            if let Some(next) = self.find(buf_state::free) {
+                self.program(next);
+                self.state[next] = buf_state::staged;
+                self.staged = Some(next);
+            }
+        }
+    }
+
+    /** Complete the in-flight frame on a `frame_finish` event.
+     *
+     * Intended to be called from the `frame_finish` ISR, after `on_frame_start`
+     * has had a chance to stage the successor. It clears the interrupt, hands
+     * the just-finished buffer back to the consumer (marking it `ready`) and
+     * promotes the buffer staged at the last `frame_start` to in-flight. If
+     * nothing was staged — no buffer was free — the peripheral is still pointed
+     * at the finished buffer and will overwrite it, so the frame is dropped and
+     * the buffer kept in flight.
+     */
+    pub fn on_frame_finish(&mut self) {
+        self.dvp.clear_interrupt(interrupt::frame_finish);
+        let completed = match self.in_flight {
+            Some(i) => i,
+            None => return,
+        };
+        let finished = &self.dvp.stats.frames_finished;
+        finished.set(finished.get() + 1);
+        match self.staged.take() {
+            Some(next) => {
+                self.state[completed] = buf_state::ready;
+                self.state[next] = buf_state::in_flight;
+                self.in_flight = Some(next);
+            }
+            None => {
+                let dropped = &self.dvp.stats.frames_dropped;
+                dropped.set(dropped.get() + 1);
+            }
+        }
+    }
+
+    /** Take ownership of a completed buffer, if one is ready */
+    pub fn take_frame(&mut self) -> Option<frame_buf> {
+        self.find(buf_state::ready).map(|i| {
+            self.state[i] = buf_state::taken;
+            self.bufs[i]
+        })
+    }
+
+    /** Return a buffer previously handed out by `take_frame` to the free pool */
+    pub fn release_frame(&mut self, buf: frame_buf) {
+        if let Some(i) = self.bufs.iter().position(|&b| b == buf) {
+            if self.state[i] == buf_state::taken {
+                self.state[i] = buf_state::free;
+            }
+        }
+    }
 }